@@ -6,22 +6,102 @@ use std::path::{Path, PathBuf}; // Importing path manipulation functionalities
 use std::{fs}; // Importing fs for file system operations
 use std::sync::Mutex; // Importing Mutex for safe shared access to mutable data across threads
 
-// Type alias for command handlers: a function that takes a vector of string references and a boxed Write
-type CmdHandler = fn(&[&String], Box<dyn Write>);
+// Type alias for command handlers: a function that takes a vector of string
+// references and a boxed Write, returning a structured error on failure.
+type CmdHandler = fn(&[&String], Box<dyn Write>) -> Result<(), CommandError>;
 // A HashMap mapping command names (String) to their handlers (CmdHandler)
 type CmdMap = HashMap<String, CmdHandler>;
 
+// Startup configuration loaded from `~/.rustshellrc`: user aliases and a small
+// map of `set key value` options.
+#[derive(Default)]
+struct Config {
+    aliases: HashMap<String, String>,
+    options: HashMap<String, String>,
+}
+
+// A structured command failure, giving every error a single display path and
+// a matching exit status for `$?`.
+enum CommandError {
+    CommandNotFound(String),
+    FileNotFound(String),
+    DirectoryNotFound(String),
+    NotDirectory(String),
+    PermissionDenied(String),
+    RedirectionFailed(String),
+}
+
+impl CommandError {
+    // Classify an I/O error against `path` into the right variant.
+    fn from_io(path: &str, err: &io::Error) -> CommandError {
+        match err.kind() {
+            io::ErrorKind::NotFound => CommandError::FileNotFound(path.to_string()),
+            io::ErrorKind::PermissionDenied => CommandError::PermissionDenied(path.to_string()),
+            // ENOTDIR has no stable `ErrorKind`, so match the raw OS error.
+            _ if err.raw_os_error() == Some(20) => CommandError::NotDirectory(path.to_string()),
+            _ => CommandError::RedirectionFailed(format!("{}: {}", path, err)),
+        }
+    }
+
+    // The exit status this error should set `$?` to.
+    fn code(&self) -> i32 {
+        match self {
+            CommandError::CommandNotFound(_) => 127,
+            _ => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::CommandNotFound(cmd) => write!(f, "{}: command not found", cmd),
+            CommandError::FileNotFound(path) | CommandError::DirectoryNotFound(path) => {
+                write!(f, "{}: No such file or directory", path)
+            }
+            CommandError::NotDirectory(path) => write!(f, "{}: Not a directory", path),
+            CommandError::PermissionDenied(path) => write!(f, "{}: Permission denied", path),
+            CommandError::RedirectionFailed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+// Report a command error: print it and record its status in `$?`.
+fn report(err: CommandError) {
+    eprintln!("{}", err);
+    *LAST_STATUS.lock().unwrap() = err.code();
+}
+
 // Lazy static variable to hold the command map
 lazy_static! {
-    pub static ref CMD_MAP: CmdMap = {
+    static ref CMD_MAP: CmdMap = {
         let mut cmd_map = CmdMap::new(); // Create a new command map
         cmd_map.insert("cd".to_string(), handle_cd); // Add 'cd' command handler
         cmd_map.insert("pwd".to_string(), handle_pwd); // Add 'pwd' command handler
+        cmd_map.insert("history".to_string(), handle_history); // Add 'history' command handler
+        cmd_map.insert("alias".to_string(), handle_alias); // Add 'alias' command handler
+        cmd_map.insert("unalias".to_string(), handle_unalias); // Add 'unalias' command handler
         cmd_map // Return the populated command map
     };
 
     // A Mutex-protected variable holding the current working directory
     static ref CURRENT_DIR: Mutex<PathBuf> = Mutex::new(PathBuf::from("/"));
+
+    // In-memory command history, persisted to `~/.rustshell_history`.
+    static ref HISTORY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    // Maximum number of history entries to keep in memory and on disk.
+    static ref HISTORY_LIMIT: Mutex<usize> = Mutex::new(1000);
+
+    // Shell-local variables set via `NAME=value` (checked before the environment).
+    static ref SHELL_VARS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+
+    // Exit status of the last external command or reported command error,
+    // exposed as `$?`.
+    static ref LAST_STATUS: Mutex<i32> = Mutex::new(0);
+
+    // User aliases and options read from `~/.rustshellrc`.
+    static ref CONFIG: Mutex<Config> = Mutex::new(Config::default());
 }
 
 // Function to check if a command is a built-in shell command
@@ -34,7 +114,7 @@ fn is_builtin(cmd: &str) -> Option<&'static str> {
 }
 
 // Function to handle the `cd` (change directory) command
-fn handle_cd(args: &[&String], mut handle: Box<dyn Write>) {
+fn handle_cd(args: &[&String], mut handle: Box<dyn Write>) -> Result<(), CommandError> {
     let home_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/")); // Get the home directory
 
     // Handle tilde expansion for home directory
@@ -51,137 +131,976 @@ fn handle_cd(args: &[&String], mut handle: Box<dyn Write>) {
         PathBuf::from(target) // Return the expanded target directory as PathBuf
     };
 
-    // Attempt to set the current working directory
-    if let Err(e) = env::set_current_dir(&target_dir) { // Set the current directory of the process
-        // If an error occurs, output a meaningful error message
-        let error_message = e.to_string().split(':').skip(1).collect::<Vec<&str>>().join(":").trim().to_string();
-        let error_message = if error_message.is_empty() {
-            "No such file or directory".to_string()
-        } else {
-            error_message
-        };
-        
-        writeln!(handle, "cd: {}: {}", target_dir.display(), error_message).unwrap();
-    } else {
-        // Update the global `CURRENT_DIR` to reflect the new directory
-        let mut current_dir = CURRENT_DIR.lock().unwrap();
-        *current_dir = target_dir.clone();
+    // Attempt to set the current working directory, mapping any failure onto a
+    // structured error (so `cd /nonexistent` and `cd /etc/hosts` are distinct).
+    match env::set_current_dir(&target_dir) {
+        Ok(()) => {
+            // Update the global `CURRENT_DIR` to reflect the new directory
+            let mut current_dir = CURRENT_DIR.lock().unwrap();
+            *current_dir = target_dir.clone();
 
-        writeln!(handle, "Changed directory to: {}", target_dir.display()).unwrap();
+            writeln!(handle, "Changed directory to: {}", target_dir.display()).unwrap();
+            Ok(())
+        }
+        Err(e) => {
+            let path = target_dir.display().to_string();
+            Err(match e.kind() {
+                io::ErrorKind::NotFound => CommandError::DirectoryNotFound(path),
+                io::ErrorKind::PermissionDenied => CommandError::PermissionDenied(path),
+                _ if e.raw_os_error() == Some(20) => CommandError::NotDirectory(path),
+                _ => CommandError::RedirectionFailed(format!("{}: {}", path, e)),
+            })
+        }
     }
 }
 
 // Function to handle the `pwd` (print working directory) command
-fn handle_pwd(_: &[&String], mut handle: Box<dyn Write>) {
+fn handle_pwd(_: &[&String], mut handle: Box<dyn Write>) -> Result<(), CommandError> {
     // Access the current directory from the global variable
     let current_dir = CURRENT_DIR.lock().unwrap(); // Mutex lock to safely access the current directory
     writeln!(handle, "{}", current_dir.display()).unwrap(); // Output the current directory
+    Ok(())
+}
+
+// Function to handle the `history` command: print the numbered command list
+fn handle_history(_: &[&String], mut handle: Box<dyn Write>) -> Result<(), CommandError> {
+    let history = HISTORY.lock().unwrap();
+    for (i, entry) in history.iter().enumerate() {
+        writeln!(handle, "{:>5}  {}", i + 1, entry).unwrap(); // 1-based, right-aligned index
+    }
+    Ok(())
 }
 
-// Function to handle a given command
-fn handle_cmd(cmd: &str) {
-    let args = handle_quotes(cmd); // Handle arguments with quotes
+// Function to handle the `alias` command: list aliases, show one, or define
+// new ones (which are persisted to the rc file).
+fn handle_alias(args: &[&String], mut handle: Box<dyn Write>) -> Result<(), CommandError> {
     if args.is_empty() {
-        return;
+        let config = CONFIG.lock().unwrap();
+        let mut names: Vec<&String> = config.aliases.keys().collect();
+        names.sort();
+        for name in names {
+            writeln!(handle, "alias {}='{}'", name, config.aliases[name]).unwrap();
+        }
+        return Ok(());
+    }
+
+    let mut defined = false;
+    for arg in args {
+        if let Some((name, value)) = arg.split_once('=') {
+            CONFIG.lock().unwrap().aliases.insert(name.to_string(), value.to_string());
+            defined = true;
+        } else {
+            let config = CONFIG.lock().unwrap();
+            match config.aliases.get(arg.as_str()) {
+                Some(value) => writeln!(handle, "alias {}='{}'", arg, value).unwrap(),
+                None => writeln!(handle, "alias: {}: not found", arg).unwrap(),
+            }
+        }
+    }
+    if defined {
+        save_config(); // Persist newly defined aliases
+    }
+    Ok(())
+}
+
+// Function to handle the `unalias` command: remove aliases and persist.
+fn handle_unalias(args: &[&String], _: Box<dyn Write>) -> Result<(), CommandError> {
+    let mut config = CONFIG.lock().unwrap();
+    for arg in args {
+        config.aliases.remove(arg.as_str());
+    }
+    drop(config);
+    save_config();
+    Ok(())
+}
+
+// Expand a leading alias into its (re-tokenized) definition, repeating while
+// the new leading token is itself an alias. A visited set breaks cycles.
+fn expand_aliases(mut args: Vec<String>) -> Vec<String> {
+    let mut visited = std::collections::HashSet::new();
+    while let Some(first) = args.first().cloned() {
+        let replacement = CONFIG.lock().unwrap().aliases.get(&first).cloned();
+        match replacement {
+            Some(value) if visited.insert(first) => {
+                let mut expanded = handle_quotes(&value); // Re-tokenize the alias body
+                expanded.extend_from_slice(&args[1..]);
+                args = expanded;
+            }
+            _ => break,
+        }
+    }
+    args
+}
+
+// Path to the rc file (`~/.rustshellrc`).
+fn config_path() -> PathBuf {
+    let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
+    home.join(".rustshellrc")
+}
+
+// Load the rc file at startup, parsing `alias name=value` and `set key value`
+// lines, and applying known options (currently `history-limit`).
+fn load_config() {
+    let contents = match fs::read_to_string(config_path()) {
+        Ok(contents) => contents,
+        Err(_) => return,
+    };
+
+    let mut config = CONFIG.lock().unwrap();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("alias ") {
+            if let Some((name, value)) = rest.split_once('=') {
+                let value = value.trim().trim_matches(|c| c == '\'' || c == '"');
+                config.aliases.insert(name.trim().to_string(), value.to_string());
+            }
+        } else if let Some(rest) = line.strip_prefix("set ") {
+            let mut parts = rest.splitn(2, char::is_whitespace);
+            if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+                config.options.insert(key.to_string(), value.trim().to_string());
+            }
+        }
+    }
+
+    // Apply known options.
+    if let Some(limit) = config.options.get("history-limit").and_then(|v| v.parse().ok()) {
+        *HISTORY_LIMIT.lock().unwrap() = limit;
+    }
+}
+
+// Persist the current aliases and options back to the rc file.
+fn save_config() {
+    let config = CONFIG.lock().unwrap();
+    let mut out = String::new();
+
+    let mut names: Vec<&String> = config.aliases.keys().collect();
+    names.sort();
+    for name in names {
+        out.push_str(&format!("alias {}={}\n", name, config.aliases[name]));
+    }
+
+    let mut keys: Vec<&String> = config.options.keys().collect();
+    keys.sort();
+    for key in keys {
+        out.push_str(&format!("set {} {}\n", key, config.options[key]));
+    }
+
+    let _ = fs::write(config_path(), out);
+}
+
+// Path to the on-disk history file (`~/.rustshell_history`).
+fn history_path() -> PathBuf {
+    let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
+    home.join(".rustshell_history")
+}
+
+// Load the persisted history at startup, keeping only the most recent entries
+// up to the configured limit.
+fn load_history() {
+    if let Ok(contents) = fs::read_to_string(history_path()) {
+        let mut history = HISTORY.lock().unwrap();
+        for line in contents.lines() {
+            if !line.is_empty() {
+                history.push(line.to_string());
+            }
+        }
+        let limit = *HISTORY_LIMIT.lock().unwrap();
+        let len = history.len();
+        if len > limit {
+            history.drain(0..len - limit); // Drop the oldest overflow
+        }
+    }
+}
+
+// Persist the in-memory history to disk, trimmed to the configured limit.
+fn save_history() {
+    let history = HISTORY.lock().unwrap();
+    let limit = *HISTORY_LIMIT.lock().unwrap();
+    let start = history.len().saturating_sub(limit);
+    let _ = fs::write(history_path(), history[start..].join("\n"));
+}
+
+// Append a command to the in-memory history, enforcing the limit.
+fn push_history(line: &str) {
+    let mut history = HISTORY.lock().unwrap();
+    history.push(line.to_string());
+    let limit = *HISTORY_LIMIT.lock().unwrap();
+    let len = history.len();
+    if len > limit {
+        history.drain(0..len - limit);
+    }
+}
+
+// Expand a `!n` / `!!` history reference into the referenced command. A line
+// starting with `!` must consist of the reference alone; any text after it
+// makes the reference unresolvable. Returns the (possibly unchanged) command,
+// or `None` when the reference does not resolve to a stored entry.
+fn expand_history(cmd: &str) -> Option<String> {
+    let trimmed = cmd.trim_start();
+    if !trimmed.starts_with('!') {
+        return Some(cmd.to_string());
+    }
+
+    let history = HISTORY.lock().unwrap();
+    let rest = &trimmed[1..];
+    let entry = if rest == "!" {
+        history.last().cloned() // `!!` re-runs the previous command
+    } else if let Ok(n) = rest.parse::<usize>() {
+        if n >= 1 {
+            history.get(n - 1).cloned() // `!n` re-runs the n-th entry (1-based)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    match entry {
+        Some(entry) => Some(entry),
+        None => {
+            eprintln!("{}: event not found", trimmed);
+            None
+        }
+    }
+}
+
+// Read a line of input with interactive editing: up/down history recall and
+// Ctrl-R reverse search, implemented with crossterm raw mode. Returns `None`
+// on end-of-input (Ctrl-D on an empty line). Falls back to cooked input when
+// raw mode is unavailable (e.g. a piped stdin).
+fn read_line(prompt: &str) -> Option<String> {
+    use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+
+    print!("{}", prompt);
+    io::stdout().flush().ok();
+
+    if enable_raw_mode().is_err() {
+        let mut line = String::new();
+        return match io::stdin().read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
+            Err(_) => None,
+        };
+    }
+
+    let outcome = edit_line(prompt);
+    let _ = disable_raw_mode();
+    println!(); // Move to a fresh line after the user presses Enter
+    outcome
+}
+
+// Repaint the current input line: carriage return, clear to end of line, then
+// the prompt and buffer.
+fn redraw(prompt: &str, buffer: &str) {
+    print!("\r\x1b[K{}{}", prompt, buffer);
+    io::stdout().flush().ok();
+}
+
+// The core raw-mode editing loop. Cursor editing is kept to the end of the
+// line (append / backspace); arrows walk the history and Ctrl-R searches it.
+fn edit_line(prompt: &str) -> Option<String> {
+    use crossterm::event::{read, Event, KeyCode, KeyEvent, KeyModifiers};
+
+    let history = HISTORY.lock().unwrap().clone(); // Snapshot for this edit session
+    let mut buffer = String::new();
+    let mut hist_idx = history.len(); // Points just past the newest entry
+
+    loop {
+        match read() {
+            Ok(Event::Key(KeyEvent { code, modifiers, .. })) => match (code, modifiers) {
+                (KeyCode::Enter, _) => return Some(buffer),
+                (KeyCode::Tab, _) => {
+                    complete(&mut buffer);
+                    redraw(prompt, &buffer);
+                }
+                // Ctrl-D on an empty line signals EOF; on a non-empty line it's a no-op.
+                (KeyCode::Char('d'), KeyModifiers::CONTROL) if buffer.is_empty() => return None,
+                (KeyCode::Char('d'), KeyModifiers::CONTROL) => {}
+                (KeyCode::Char('c'), KeyModifiers::CONTROL) => return Some(String::new()),
+                (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                    if let Some(found) = reverse_search(&history) {
+                        buffer = found;
+                    }
+                    redraw(prompt, &buffer);
+                }
+                (KeyCode::Char(c), _) => {
+                    buffer.push(c);
+                    redraw(prompt, &buffer);
+                }
+                (KeyCode::Backspace, _) => {
+                    buffer.pop();
+                    redraw(prompt, &buffer);
+                }
+                (KeyCode::Up, _) if hist_idx > 0 => {
+                    hist_idx -= 1;
+                    buffer = history[hist_idx].clone();
+                    redraw(prompt, &buffer);
+                }
+                (KeyCode::Down, _) if hist_idx < history.len() => {
+                    hist_idx += 1;
+                    buffer = history.get(hist_idx).cloned().unwrap_or_default();
+                    redraw(prompt, &buffer);
+                }
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(_) => return None,
+        }
     }
+}
+
+// Find the newest history entry before `before` that contains `query`.
+fn search_back(history: &[String], query: &str, before: usize) -> Option<(usize, String)> {
+    if query.is_empty() {
+        return None;
+    }
+    let mut i = before;
+    while i > 0 {
+        i -= 1;
+        if history[i].contains(query) {
+            return Some((i, history[i].clone()));
+        }
+    }
+    None
+}
+
+// Ctrl-R reverse incremental search. Returns the accepted match (on Enter) or
+// `None` if the search is cancelled (Esc / Ctrl-G).
+fn reverse_search(history: &[String]) -> Option<String> {
+    use crossterm::event::{read, Event, KeyCode, KeyEvent, KeyModifiers};
+
+    let mut query = String::new();
+    let mut matched: Option<String> = None;
+    let mut from = history.len();
+
+    let render = |query: &str, matched: &Option<String>| {
+        print!(
+            "\r\x1b[K(reverse-i-search)`{}': {}",
+            query,
+            matched.clone().unwrap_or_default()
+        );
+        io::stdout().flush().ok();
+    };
+    render(&query, &matched);
+
+    loop {
+        match read() {
+            Ok(Event::Key(KeyEvent { code, modifiers, .. })) => match (code, modifiers) {
+                (KeyCode::Enter, _) => return matched,
+                (KeyCode::Esc, _) | (KeyCode::Char('g'), KeyModifiers::CONTROL) => return None,
+                (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                    if let Some((i, m)) = search_back(history, &query, from) {
+                        from = i;
+                        matched = Some(m);
+                    }
+                    render(&query, &matched);
+                }
+                (KeyCode::Char(c), _) => {
+                    query.push(c);
+                    from = history.len();
+                    matched = search_back(history, &query, from).map(|(i, m)| {
+                        from = i;
+                        m
+                    });
+                    render(&query, &matched);
+                }
+                (KeyCode::Backspace, _) => {
+                    query.pop();
+                    from = history.len();
+                    matched = search_back(history, &query, from).map(|(i, m)| {
+                        from = i;
+                        m
+                    });
+                    render(&query, &matched);
+                }
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(_) => return None,
+        }
+    }
+}
+
+// Function to handle a given command line (already resolved of any `!`
+// history reference), which may contain one or more pipeline segments
+// separated by `|`.
+fn handle_cmd(cmd: &str) -> Result<(), CommandError> {
+    let args = handle_quotes(cmd); // Handle arguments with quotes (and `$` expansion)
+    if args.is_empty() {
+        return Ok(());
+    }
+
+    // Expand a leading alias into its definition (re-tokenized), guarding
+    // against infinite alias recursion.
+    let args = expand_aliases(args);
 
-    // Parse redirection (e.g., >, 1>, 2>, >>, etc.)
-    let (args, output_file, error_file, append_output, append_error) = parse_redirection(&args);
+    // A line consisting solely of `NAME=value` tokens sets shell-local
+    // variables and runs no command.
+    if args.iter().all(|token| is_assignment(token)) {
+        let mut vars = SHELL_VARS.lock().unwrap();
+        for token in &args {
+            if let Some((name, value)) = token.split_once('=') {
+                vars.insert(name.to_string(), value.to_string());
+            }
+        }
+        return Ok(());
+    }
+
+    // Split the tokenized arguments into pipeline segments on `|` tokens.
+    let mut segments: Vec<Vec<String>> = Vec::new();
+    let mut current: Vec<String> = Vec::new();
+    for arg in args {
+        if arg == "|" {
+            segments.push(std::mem::take(&mut current)); // End the current segment
+        } else {
+            current.push(arg);
+        }
+    }
+    segments.push(current); // Push the trailing segment
+
+    if segments.len() == 1 {
+        run_single(&segments[0]) // Fast path: a plain command with no pipe
+    } else {
+        run_pipeline(&segments) // Streamed chain of commands
+    }
+}
+
+// Run a single, un-piped command with optional redirection (the original path).
+fn run_single(args: &Vec<String>) -> Result<(), CommandError> {
+    use std::process::{Command, Stdio};
+
+    // Parse the redirect list (e.g., <, >, 1>, 2>, >>, 2>&1, etc.)
+    let (args, redirects) = parse_redirection(args);
+    if args.is_empty() {
+        return Ok(());
+    }
     let cmd = args[0]; // Extract the command from arguments
     let args = &args[1..]; // Get the remaining arguments
 
-    let mut handle: Box<dyn Write> = Box::new(std::io::stdout()); // Default output handle (stdout)
-    let mut stderr_handle: Box<dyn Write> = Box::new(std::io::stderr()); // Default error handle (stderr)
-
-    // Handle output redirection
-    if let Some(output_file) = output_file {
-        handle = if append_output {
-            match fs::OpenOptions::new().append(true).create(true).open(output_file) {
-                Ok(file) => Box::new(std::io::BufWriter::new(file)),
-                Err(e) => {
-                    eprintln!("Failed to open output file {}: {}", output_file, e);
-                    return;
+    // Replay the redirect list to build the stdin/stdout/stderr handles; later
+    // redirects override earlier ones.
+    let (stdin_file, stdout_sink, stderr_sink) = replay_redirects(&redirects)?;
+
+    // Check for built-in commands (e.g., 'cd', 'pwd')
+    if is_builtin(cmd).is_some() || CMD_MAP.contains_key(cmd.as_str()) {
+        run_builtin(cmd, args, stdout_sink.into_writer())?;
+    } else {
+        // For external commands, check if the command exists in the system's PATH
+        check_cmd_in_path(cmd)?;
+        let mut command = Command::new(cmd);
+        command.args(args);
+        if let Some(file) = stdin_file {
+            command.stdin(Stdio::from(file));
+        }
+        command.stdout(stdout_sink.into_stdio());
+        command.stderr(stderr_sink.into_stdio());
+
+        match command.spawn() {
+            Ok(mut child) => {
+                let status = child.wait();
+                *LAST_STATUS.lock().unwrap() = status.ok().and_then(|s| s.code()).unwrap_or(1);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(CommandError::CommandNotFound(cmd.to_string()));
+            }
+            Err(e) => {
+                return Err(CommandError::RedirectionFailed(format!("{}: {}", cmd, e)));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Run a chain of pipeline segments, wiring each child's stdout into the next
+// child's stdin. The first segment reads inherited stdin and the last writes
+// to the current output (terminal or redirection target). All external
+// segments are spawned before any are waited on, to avoid pipe deadlocks.
+fn run_pipeline(segments: &[Vec<String>]) -> Result<(), CommandError> {
+    use std::io::Read;
+    use std::process::{Child, ChildStdout, Command, Stdio};
+
+    let last_idx = segments.len() - 1;
+    let mut prev_stdout: Option<ChildStdout> = None; // Piped output from the previous external stage
+    let mut pending_bytes: Option<Vec<u8>> = None; // Buffered output from a preceding built-in
+    let mut children: Vec<Child> = Vec::new();
+    let mut failure: Option<CommandError> = None; // Set on abort, so children can still be waited on below
+
+    for (i, segment) in segments.iter().enumerate() {
+        let is_first = i == 0;
+        let is_last = i == last_idx;
+
+        // Redirection only applies to the first and last segments of a pipeline.
+        let (seg_args, redirects) = if is_first || is_last {
+            parse_redirection(segment)
+        } else {
+            (segment.iter().collect::<Vec<&String>>(), Vec::new())
+        };
+
+        if seg_args.is_empty() {
+            continue;
+        }
+        let cmd = seg_args[0];
+        let args = &seg_args[1..];
+
+        // Replay this segment's redirects (only the first/last ever carry any).
+        // A failure here aborts the pipeline, but must still fall through to
+        // the wait loop below so any already-spawned stages aren't leaked.
+        let (stdin_file, stdout_sink, stderr_sink) = match replay_redirects(&redirects) {
+            Ok(sinks) => sinks,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        };
+
+        // Whether this segment's stderr duplicates stdout (`2>&1`). For a
+        // non-last stage, stdout is about to be piped to the next command
+        // rather than resolved to `stdout_sink`, so the duplication has to
+        // follow it into that pipe instead of being resolved eagerly above.
+        let merge_stderr = redirects
+            .iter()
+            .any(|r| r.direction == Direction::Out && r.from_fd == 2 && r.target == "&1");
+
+        // Built-in segment: run in-process, writing either to the final handle
+        // (when last) or into a buffer that feeds the next stage. As above, a
+        // failure breaks out to the wait loop rather than returning directly.
+        if is_builtin(cmd).is_some() || CMD_MAP.contains_key(cmd.as_str()) {
+            let result = if is_last {
+                run_builtin(cmd, args, stdout_sink.into_writer())
+            } else {
+                let buf = SharedBuf::new();
+                let result = run_builtin(cmd, args, Box::new(buf.clone()));
+                if result.is_ok() {
+                    pending_bytes = Some(buf.take());
                 }
+                result
+            };
+            if let Err(e) = result {
+                failure = Some(e);
+                break;
             }
+            prev_stdout = None;
+            continue;
+        }
+
+        // External segment: wire up stdin/stdout/stderr for this stage.
+        let mut command = Command::new(cmd);
+        command.args(args);
+
+        // stdin comes from the previous external stage, a preceding built-in's
+        // buffer, the first segment's `<` redirection, or the inherited terminal.
+        if let Some(out) = prev_stdout.take() {
+            command.stdin(Stdio::from(out));
+        } else if pending_bytes.is_some() {
+            command.stdin(Stdio::piped());
+        } else if let Some(file) = stdin_file {
+            command.stdin(Stdio::from(file));
+        } else {
+            command.stdin(Stdio::inherit());
+        }
+
+        // stdout is piped to the next stage, or (for the last stage) goes to the
+        // replayed redirection sink (file or inherited terminal). stderr
+        // follows the same split, except a non-last `2>&1` is piped too (see
+        // `merge_stderr` above) instead of using its eagerly-resolved sink.
+        if is_last {
+            command.stdout(stdout_sink.into_stdio());
+            command.stderr(stderr_sink.into_stdio());
+        } else if merge_stderr {
+            command.stdout(Stdio::piped());
+            command.stderr(Stdio::piped());
         } else {
-            match std::fs::File::create(output_file) {
-                Ok(file) => Box::new(std::io::BufWriter::new(file)),
-                Err(e) => {
-                    eprintln!("Failed to create output file {}: {}", output_file, e);
-                    return;
+            command.stdout(Stdio::piped());
+            command.stderr(stderr_sink.into_stdio());
+        }
+
+        match command.spawn() {
+            Ok(mut child) => {
+                // Feed any buffered bytes from a preceding built-in.
+                if let Some(bytes) = pending_bytes.take()
+                    && let Some(mut stdin) = child.stdin.take()
+                {
+                    let _ = stdin.write_all(&bytes);
                 }
+                if merge_stderr && !is_last {
+                    // std has no way to dup stdout and stderr onto the same
+                    // pipe, so merge them by hand: drain stderr on its own
+                    // thread (so a full stderr pipe can't deadlock against
+                    // our read of stdout) and hand the combined bytes to the
+                    // next stage the same way a preceding built-in's output
+                    // is fed forward.
+                    let err_reader = child.stderr.take().map(|mut err| {
+                        std::thread::spawn(move || {
+                            let mut buf = Vec::new();
+                            let _ = err.read_to_end(&mut buf);
+                            buf
+                        })
+                    });
+                    let mut combined = Vec::new();
+                    if let Some(mut out) = child.stdout.take() {
+                        let _ = out.read_to_end(&mut combined);
+                    }
+                    if let Some(reader) = err_reader {
+                        combined.extend(reader.join().unwrap_or_default());
+                    }
+                    pending_bytes = Some(combined);
+                } else if !is_last {
+                    prev_stdout = child.stdout.take();
+                }
+                children.push(child);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                failure = Some(CommandError::CommandNotFound(cmd.to_string()));
+                break;
+            }
+            Err(e) => {
+                failure = Some(CommandError::RedirectionFailed(format!("{}: {}", cmd, e)));
+                break;
             }
         }
     }
 
-    // Handle error redirection
-    if let Some(error_file) = error_file {
-        stderr_handle = if append_error {
-            match fs::OpenOptions::new().append(true).create(true).open(error_file) {
-                Ok(file) => Box::new(std::io::BufWriter::new(file)),
-                Err(e) => {
-                    eprintln!("Failed to open error file {}: {}", error_file, e);
-                    return;
+    // Wait for every spawned child, in spawn order, to avoid deadlock — and to
+    // avoid leaking zombies from a stage that was already spawned when a later
+    // one aborted the pipeline. This runs regardless of `failure` above.
+    let had_external = !children.is_empty();
+    let mut last_code = 0;
+    for mut child in children {
+        if let Ok(status) = child.wait() {
+            last_code = status.code().unwrap_or(1);
+        }
+    }
+
+    if let Some(err) = failure {
+        return Err(err);
+    }
+
+    if had_external {
+        *LAST_STATUS.lock().unwrap() = last_code;
+    }
+    Ok(())
+}
+
+// Dispatch a built-in command by name to its handler.
+fn run_builtin(cmd: &String, args: &[&String], handle: Box<dyn Write>) -> Result<(), CommandError> {
+    if let Some(builtin_message) = is_builtin(cmd)
+        && cmd != "cd" && cmd != "pwd"
+    {
+        // Print the message for built-in commands other than 'cd' and 'pwd'
+        let mut handle = handle;
+        writeln!(handle, "{}", builtin_message).unwrap();
+        return Ok(());
+    }
+    if let Some(builtin_cmd_handler) = CMD_MAP.get(cmd.as_str()) {
+        return builtin_cmd_handler(args, handle); // Execute the corresponding handler
+    }
+    Ok(())
+}
+
+// Open a file for writing, creating it when necessary and truncating or
+// appending according to `append`.
+fn open_file_for_write(path: &str, append: bool) -> io::Result<fs::File> {
+    if append {
+        fs::OpenOptions::new().append(true).create(true).open(path)
+    } else {
+        fs::File::create(path)
+    }
+}
+
+// Direction of a redirect: `<` feeds input, `>`/`>>` capture output.
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    In,
+    Out,
+}
+
+// A single parsed redirect. `from_fd` is the descriptor being redirected
+// (0 for `<`, 1 for `>`, or an explicit leading digit). A `target` starting
+// with `&` (e.g. `&1`) duplicates the named descriptor instead of opening a
+// file; `append` distinguishes `>>` from `>`.
+struct Redirect {
+    from_fd: i32,
+    direction: Direction,
+    append: bool,
+    target: String,
+}
+
+// Where an output descriptor currently points while redirects are replayed.
+enum OutSink {
+    Inherit,
+    File(fs::File),
+}
+
+impl OutSink {
+    // Duplicate this sink so another descriptor can point at the same target
+    // (used to implement `2>&1`).
+    fn clone_sink(&self) -> io::Result<OutSink> {
+        Ok(match self {
+            OutSink::Inherit => OutSink::Inherit,
+            OutSink::File(file) => OutSink::File(file.try_clone()?),
+        })
+    }
+
+    // Convert the sink into a `Stdio` for an external command.
+    fn into_stdio(self) -> std::process::Stdio {
+        match self {
+            OutSink::Inherit => std::process::Stdio::inherit(),
+            OutSink::File(file) => std::process::Stdio::from(file),
+        }
+    }
+
+    // Convert the sink into a boxed writer for a built-in command.
+    fn into_writer(self) -> Box<dyn Write> {
+        match self {
+            OutSink::Inherit => Box::new(std::io::stdout()),
+            OutSink::File(file) => Box::new(std::io::BufWriter::new(file)),
+        }
+    }
+}
+
+// Replay a redirect list in order, returning the stdin file (if any) and the
+// final stdout/stderr sinks. Later redirects override earlier ones, and a
+// `N>&M` duplication captures whatever descriptor M points at right then.
+fn replay_redirects(redirects: &[Redirect]) -> Result<(Option<fs::File>, OutSink, OutSink), CommandError> {
+    let mut stdin_file: Option<fs::File> = None;
+    let mut stdout_sink = OutSink::Inherit;
+    let mut stderr_sink = OutSink::Inherit;
+
+    for redirect in redirects {
+        match redirect.direction {
+            Direction::In => {
+                let file = fs::File::open(&redirect.target)
+                    .map_err(|e| CommandError::from_io(&redirect.target, &e))?;
+                stdin_file = Some(file);
+            }
+            Direction::Out => {
+                // `&N` duplicates descriptor N rather than opening a file.
+                if let Some(rest) = redirect.target.strip_prefix('&') {
+                    let duplicated = match rest {
+                        "1" => stdout_sink.clone_sink(),
+                        "2" => stderr_sink.clone_sink(),
+                        _ => Ok(OutSink::Inherit),
+                    }
+                    .map_err(|e| CommandError::RedirectionFailed(e.to_string()))?;
+                    if redirect.from_fd == 2 {
+                        stderr_sink = duplicated;
+                    } else {
+                        stdout_sink = duplicated;
+                    }
+                } else {
+                    let file = open_file_for_write(&redirect.target, redirect.append)
+                        .map_err(|e| CommandError::from_io(&redirect.target, &e))?;
+                    if redirect.from_fd == 2 {
+                        stderr_sink = OutSink::File(file);
+                    } else {
+                        stdout_sink = OutSink::File(file);
+                    }
                 }
             }
-        } else {
-            match std::fs::File::create(error_file) {
-                Ok(file) => Box::new(std::io::BufWriter::new(file)),
-                Err(e) => {
-                    eprintln!("Failed to create error file {}: {}", error_file, e);
-                    return;
+        }
+    }
+
+    Ok((stdin_file, stdout_sink, stderr_sink))
+}
+
+// A shared, in-memory byte sink used to capture a built-in's output so it can
+// be fed into the next pipeline stage's stdin.
+#[derive(Clone)]
+struct SharedBuf(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl SharedBuf {
+    fn new() -> Self {
+        SharedBuf(std::rc::Rc::new(std::cell::RefCell::new(Vec::new())))
+    }
+
+    // Return a copy of the captured bytes.
+    fn take(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Complete the word at the end of `buffer` in place. The first token is
+// completed against shell commands; later tokens against filesystem paths.
+// On a unique match the completion is inserted with a trailing space (or `/`
+// for directories); on several matches the common prefix is filled in, or the
+// candidates are listed in columns when no further prefix is shared.
+fn complete(buffer: &mut String) {
+    let start = buffer.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+    let word = buffer[start..].to_string();
+
+    if start == 0 {
+        // First token: complete against builtins, CMD_MAP keys and PATH.
+        let mut names = command_candidates(&word);
+        names.sort();
+        names.dedup();
+        let dirs = vec![false; names.len()];
+        apply_completion(buffer, start, &names, &dirs);
+    } else {
+        // Later tokens: complete against filesystem paths.
+        let mut cands = path_candidates(&word);
+        cands.sort_by(|a, b| a.0.cmp(&b.0));
+        cands.dedup_by(|a, b| a.0 == b.0);
+        let names: Vec<String> = cands.iter().map(|c| c.0.clone()).collect();
+        let dirs: Vec<bool> = cands.iter().map(|c| c.1).collect();
+        apply_completion(buffer, start, &names, &dirs);
+    }
+}
+
+// Apply a set of completion candidates to `buffer`, replacing the text from
+// `start` to the end. `dirs[i]` marks whether candidate `i` is a directory.
+fn apply_completion(buffer: &mut String, start: usize, names: &[String], dirs: &[bool]) {
+    if names.is_empty() {
+        return;
+    }
+
+    if names.len() == 1 {
+        buffer.truncate(start);
+        buffer.push_str(&names[0]);
+        buffer.push_str(if dirs[0] { "/" } else { " " });
+        return;
+    }
+
+    // Fill in the longest common prefix if it extends the current word;
+    // otherwise list the candidates in columns.
+    let prefix = longest_common_prefix(names);
+    if prefix.len() > buffer.len() - start {
+        buffer.truncate(start);
+        buffer.push_str(&prefix);
+    } else {
+        print_columns(names);
+    }
+}
+
+// Gather command-name candidates matching `prefix`: the builtin names, the
+// CMD_MAP keys, and every executable filename found on the PATH.
+fn command_candidates(prefix: &str) -> Vec<String> {
+    let mut candidates = Vec::new();
+
+    for name in ["cd", "pwd"] {
+        if is_builtin(name).is_some() && name.starts_with(prefix) {
+            candidates.push(name.to_string());
+        }
+    }
+    for key in CMD_MAP.keys() {
+        if key.starts_with(prefix) {
+            candidates.push(key.clone());
+        }
+    }
+    if let Ok(paths) = env::var("PATH") {
+        for path in paths.split(':') {
+            if let Ok(entries) = fs::read_dir(path) {
+                for entry in entries.flatten() {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    if name.starts_with(prefix) {
+                        candidates.push(name);
+                    }
                 }
             }
         }
     }
 
-    // Check for built-in commands (e.g., 'cd', 'pwd')
-    if let Some(builtin_message) = is_builtin(cmd) {
-        if cmd != "cd" && cmd != "pwd" { // Print the message for built-in commands other than 'cd' and 'pwd'
-            writeln!(handle, "{}", builtin_message).unwrap();
-        }
-    } else if let Some(builtin_cmd_handler) = CMD_MAP.get(cmd) {
-        // If command is in CMD_MAP, execute the corresponding handler
-        builtin_cmd_handler(args, handle);
-    } else if let Some(path) = check_cmd_in_path(cmd) {
-        // For external commands, check if the command exists in the system's PATH
-        let cmd_display = path.file_name()
-            .map(|os_str| os_str.to_string_lossy().into_owned())
-            .unwrap_or_else(|| cmd.to_string());
-
-        // Run the external command with redirection
-        let output = std::process::Command::new(cmd)
-            .args(args)
-            .output();
-
-        match output {
-            Ok(output) => {
-                handle.write_all(&output.stdout).unwrap();
-                stderr_handle.write_all(&output.stderr).unwrap();
+    candidates
+}
+
+// Gather filesystem-path candidates matching `word`, expanding a leading `~`
+// to the home directory for the directory that is actually scanned while
+// preserving the user's typed directory text in the inserted candidate.
+// Each result is `(token_to_insert, is_directory)`.
+fn path_candidates(word: &str) -> Vec<(String, bool)> {
+    let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
+    let expanded = if let Some(rest) = word.strip_prefix('~') {
+        format!("{}{}", home.to_string_lossy(), rest)
+    } else {
+        word.to_string()
+    };
+
+    // Split into a directory part and a file prefix, keeping the user's text.
+    let (typed_dir, file_prefix) = match word.rfind('/') {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+    let read_dir = match expanded.rfind('/') {
+        Some(i) => expanded[..=i].to_string(),
+        None => ".".to_string(),
+    };
+
+    let mut candidates = Vec::new();
+    if let Ok(entries) = fs::read_dir(&read_dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(file_prefix) {
+                let is_dir = entry.path().is_dir();
+                candidates.push((format!("{}{}", typed_dir, name), is_dir));
             }
-            Err(e) => {
-                writeln!(handle, "{}: {}", cmd_display, e).unwrap();
+        }
+    }
+
+    candidates
+}
+
+// Compute the longest common prefix shared by all candidate strings.
+fn longest_common_prefix(names: &[String]) -> String {
+    let mut prefix = match names.first() {
+        Some(first) => first.clone(),
+        None => return String::new(),
+    };
+    for name in &names[1..] {
+        while !name.starts_with(&prefix) {
+            prefix.pop();
+            if prefix.is_empty() {
+                return String::new();
             }
         }
-    } else {
-        eprintln!("{}: command not found", cmd); // If command is not found
     }
+    prefix
+}
+
+// Print completion candidates in aligned columns (below the current line).
+fn print_columns(names: &[String]) {
+    let width = names.iter().map(|s| s.len()).max().unwrap_or(0) + 2;
+    let cols = (80 / width).max(1);
+
+    print!("\r\n");
+    for (i, name) in names.iter().enumerate() {
+        print!("{:<width$}", name, width = width);
+        if (i + 1) % cols == 0 {
+            print!("\r\n");
+        }
+    }
+    if !names.len().is_multiple_of(cols) {
+        print!("\r\n");
+    }
+    io::stdout().flush().ok();
 }
 
 // Function to check if a command exists in the system's PATH
-pub fn check_cmd_in_path(cmd: &str) -> Option<PathBuf> {
+fn check_cmd_in_path(cmd: &str) -> Result<PathBuf, CommandError> {
     if let Ok(paths) = env::var("PATH") {
         for path in paths.split(':') {
             if let Ok(entries) = fs::read_dir(path) {
                 for entry in entries.flatten() {
                     if cmd == entry.file_name().to_string_lossy() {
-                        return Some(entry.path()); // Return the command path if found
+                        return Ok(entry.path()); // Return the command path if found
                     }
                 }
             }
         }
     }
-    None // Return None if not found
+    Err(CommandError::CommandNotFound(cmd.to_string())) // Not found on the PATH
 }
 
 // Function to handle external commands
@@ -206,58 +1125,72 @@ pub fn handle_path_cmd(cmd: &str, args: &[&String], mut handle: Box<dyn Write>)
     }
 }
 
-// Function to handle argument parsing and handle quotes properly
+// Function to handle argument parsing, quote handling and `$` expansion.
+// Single-quoted segments are left literal; `$NAME` / `${NAME}` (and `$?`) are
+// expanded outside single quotes and inside double quotes.
 fn handle_quotes(args_str: &str) -> Vec<String> {
     let mut args = Vec::new();
     let mut arg = String::new();
+    let chars: Vec<char> = args_str.chars().collect();
+    let mut i = 0;
     let mut inside_single_quotes = false;
     let mut inside_double_quotes = false;
-    let mut backslash = false;
-
-    // Loop through each character and handle quotes and escape characters
-    for c in args_str.chars() {
-        if !inside_single_quotes && !inside_double_quotes {
-            if backslash {
-                arg.push(c); // Handle escaped characters
-                backslash = false;
-                continue;
-            }
-            if c == '\\' {
-                backslash = true;
-                continue;
-            }
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        // Inside single quotes everything is literal until the closing quote.
+        if inside_single_quotes {
             if c == '\'' {
-                inside_single_quotes = true;
-                continue;
-            } else if c == '"' {
-                inside_double_quotes = true;
-                continue;
-            }
-            if c.is_whitespace() {
-                if !arg.is_empty() {
-                    args.push(arg.clone());
-                    arg.clear();
-                }
-                continue;
+                inside_single_quotes = false;
+            } else {
+                arg.push(c);
             }
-        } else if inside_single_quotes && c == '\'' {
-            inside_single_quotes = false;
+            i += 1;
             continue;
-        } else if inside_double_quotes {
-            if backslash {
-                backslash = false;
-                if c != '$' && c != '"' && c != '\\' {
+        }
+
+        if c == '\\' {
+            // Backslash escapes the next character. Inside double quotes only a
+            // small set is escaped; elsewhere any character is taken literally.
+            if let Some(&next) = chars.get(i + 1) {
+                if inside_double_quotes && next != '$' && next != '"' && next != '\\' {
                     arg.push('\\');
                 }
-            } else if c == '\\' {
-                backslash = true;
-                continue;
-            } else if c == '"' {
-                inside_double_quotes = false;
-                continue;
+                arg.push(next);
+                i += 2;
+            } else {
+                i += 1;
+            }
+            continue;
+        }
+        if c == '\'' && !inside_double_quotes {
+            inside_single_quotes = true;
+            i += 1;
+            continue;
+        }
+        if c == '"' {
+            inside_double_quotes = !inside_double_quotes;
+            i += 1;
+            continue;
+        }
+        if c == '$' {
+            // Expand a variable reference (never inside single quotes).
+            let (value, consumed) = expand_variable(&chars, i);
+            arg.push_str(&value);
+            i += consumed;
+            continue;
+        }
+        if !inside_double_quotes && c.is_whitespace() {
+            if !arg.is_empty() {
+                args.push(std::mem::take(&mut arg));
             }
+            i += 1;
+            continue;
         }
+
         arg.push(c); // Add character to argument
+        i += 1;
     }
     if !arg.is_empty() {
         args.push(arg); // Add last argument if not empty
@@ -265,78 +1198,145 @@ fn handle_quotes(args_str: &str) -> Vec<String> {
     args
 }
 
-// Function to parse redirection operators in the command line (e.g., >, >>, 2>, etc.)
-fn parse_redirection<'a>(args: &'a Vec<String>) -> (Vec<&'a String>, Option<&'a String>, Option<&'a String>, bool, bool) {
+// Expand the variable reference beginning at `chars[i]` (which is `$`).
+// Returns the substituted text and the number of characters consumed,
+// including the leading `$`. A lone or trailing `$` is left literal.
+fn expand_variable(chars: &[char], i: usize) -> (String, usize) {
+    let mut j = i + 1;
+    match chars.get(j) {
+        Some('{') => {
+            j += 1;
+            let mut name = String::new();
+            while j < chars.len() && chars[j] != '}' {
+                name.push(chars[j]);
+                j += 1;
+            }
+            if j < chars.len() {
+                j += 1; // consume the closing `}`
+            }
+            (lookup_var(&name), j - i)
+        }
+        Some('?') => (lookup_var("?"), (j + 1) - i),
+        Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+            let mut name = String::new();
+            while j < chars.len() && (chars[j].is_ascii_alphanumeric() || chars[j] == '_') {
+                name.push(chars[j]);
+                j += 1;
+            }
+            (lookup_var(&name), j - i)
+        }
+        _ => ("$".to_string(), 1), // not a variable reference; keep the `$`
+    }
+}
+
+// Resolve a variable name: `$?` is the last exit status, then shell-local
+// variables, then the process environment; unset names expand to "".
+fn lookup_var(name: &str) -> String {
+    if name == "?" {
+        return LAST_STATUS.lock().unwrap().to_string();
+    }
+    if let Some(value) = SHELL_VARS.lock().unwrap().get(name) {
+        return value.clone();
+    }
+    env::var(name).unwrap_or_default()
+}
+
+// Return true if `token` has the form `NAME=value` with a valid identifier.
+fn is_assignment(token: &str) -> bool {
+    match token.find('=') {
+        Some(0) | None => false,
+        Some(eq) => {
+            let mut name = token[..eq].chars();
+            matches!(name.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+                && name.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+    }
+}
+
+// Split a token into its redirect parts, if it is one. Recognizes an optional
+// leading file descriptor, a `<` or `>`/`>>` operator, and any target glued to
+// the operator (such as the `&1` in `2>&1`). Returns
+// `(from_fd, direction, append, glued_target)`.
+fn split_redirect(tok: &str) -> Option<(i32, Direction, bool, Option<String>)> {
+    // Peel off any leading descriptor digits.
+    let digits_end = tok.find(|c: char| !c.is_ascii_digit()).unwrap_or(tok.len());
+    let (digits, rest) = tok.split_at(digits_end);
+
+    if let Some(target) = rest.strip_prefix('<') {
+        // Input redirect defaults to descriptor 0.
+        let from_fd = digits.parse().unwrap_or(0);
+        let glued = (!target.is_empty()).then(|| target.to_string());
+        Some((from_fd, Direction::In, false, glued))
+    } else if let Some(after) = rest.strip_prefix('>') {
+        // Output redirect defaults to descriptor 1; `>>` means append.
+        let from_fd = digits.parse().unwrap_or(1);
+        let (append, target) = match after.strip_prefix('>') {
+            Some(t) => (true, t),
+            None => (false, after),
+        };
+        let glued = (!target.is_empty()).then(|| target.to_string());
+        Some((from_fd, Direction::Out, append, glued))
+    } else {
+        None
+    }
+}
+
+// Parse a segment's tokens into its plain arguments and an ordered list of
+// redirects. A redirect's target is the text glued to the operator (as in
+// `2>&1`) or, failing that, the following token.
+fn parse_redirection<'a>(args: &'a Vec<String>) -> (Vec<&'a String>, Vec<Redirect>) {
     let mut cmd_args = Vec::new();
-    let mut output_file = None;
-    let mut error_file = None;
-    let mut append_output = false;
-    let mut append_error = false;
+    let mut redirects = Vec::new();
 
-    // Iterate through arguments and check for redirection symbols
     let mut i = 0;
     while i < args.len() {
-        match args[i].as_str() {
-            ">" | "1>" => {
-                if i + 1 < args.len() {
-                    output_file = Some(&args[i + 1]);
-                    i += 2;
-                    continue;
-                }
-            }
-            "2>" => {
-                if i + 1 < args.len() {
-                    error_file = Some(&args[i + 1]);
-                    i += 2;
-                    continue;
-                }
-            }
-            ">>" | "1>>" => {
-                if i + 1 < args.len() {
-                    output_file = Some(&args[i + 1]);
-                    append_output = true;
-                    i += 2;
-                    continue;
-                }
-            }
-            "2>>" => {
-                if i + 1 < args.len() {
-                    error_file = Some(&args[i + 1]);
-                    append_error = true;
-                    i += 2;
-                    continue;
-                }
-            }
-            _ => {
-                cmd_args.push(&args[i]);
-            }
+        if let Some((from_fd, direction, append, glued)) = split_redirect(args[i].as_str()) {
+            let target = if let Some(target) = glued {
+                target
+            } else if i + 1 < args.len() {
+                i += 1;
+                args[i].clone()
+            } else {
+                // Dangling redirect with no target; drop it.
+                i += 1;
+                continue;
+            };
+            redirects.push(Redirect { from_fd, direction, append, target });
+        } else {
+            cmd_args.push(&args[i]);
         }
         i += 1;
     }
-    (cmd_args, output_file, error_file, append_output, append_error)
+
+    (cmd_args, redirects)
 }
 
 // Main function
 fn main() {
-    let stdin = io::stdin();
-    let mut input = String::new();
-
-    loop {
-        // Print prompt
-        print!("$ ");
-        io::stdout().flush().unwrap();
-
-        // Read input
-        stdin.read_line(&mut input).unwrap();
+    load_config(); // Load aliases and options from the rc file
+    load_history(); // Restore history from the previous session
 
+    // `None` from `read_line` means end-of-input.
+    while let Some(line) = read_line("$ ") {
         // Handle command
-        let cmd = input.trim();
+        let cmd = line.trim();
         if cmd.is_empty() {
-            input.clear();
             continue;
         }
-        handle_cmd(cmd); // Handle the entered command
-        input.clear();
+
+        // Resolve a `!n` / `!!` reference line before it is recorded, so
+        // history stores (and later `!!` calls re-run) the actual command
+        // rather than the literal reference that was just typed.
+        let cmd = match expand_history(cmd) {
+            Some(cmd) => cmd,
+            None => continue,
+        };
+
+        push_history(&cmd); // Record the command for recall and `!` expansion
+        if let Err(e) = handle_cmd(&cmd) {
+            report(e); // Single path for command errors, also sets `$?`
+        }
     }
-}
 
+    save_history(); // Persist history on exit
+}